@@ -7,31 +7,222 @@ use actix_service::{map_config, Service};
 use actix_web::{
     cookie::Cookie,
     dev::{AppConfig, ServiceRequest},
-    error::{ErrorBadRequest, ErrorUnauthorized},
+    error::{ErrorBadRequest, ErrorForbidden, ErrorUnauthorized},
     web, App, HttpRequest, HttpResponse,
 };
-use actix_web_httpauth::{extractors::bearer::BearerAuth, middleware::HttpAuthentication};
 use anyhow::{Context, Result};
 use chrono::prelude::*;
 use futures_util::FutureExt;
 use futures_util::TryFutureExt;
-use hmac::{Hmac, NewMac};
-use jwt::{SignWithKey, VerifyWithKey};
+use hmac::{Hmac, Mac, NewMac};
+use jwt::{PKeyWithDigest, SignWithKey, VerifyWithKey};
 use log::*;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use serde::{Deserialize, Serialize};
-use sha2::Sha512;
-use std::collections::HashSet;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use time::ext::NumericalDuration;
+use uuid::Uuid;
 
 type Token<S> = jwt::Token<jwt::Header, JWTClaims, S>;
-type SignedToken = Token<jwt::token::Signed>;
 
-#[derive(Serialize, Deserialize)]
+/// Default access-token lifetime (minutes) used when the configuration does not
+/// override it. Kept short: clients renew through `/auth/refresh`.
+const DEFAULT_ACCESS_TOKEN_MINUTES: i64 = 5;
+/// Max age (days) of the `refresh_token` and `user_id` cookies. It is not
+/// passed to the backend when the refresh token is stored.
+const REFRESH_TOKEN_DAYS: i64 = 30;
+
+/// A JWT signing key together with the algorithm it implements. The keyring
+/// holds exactly one of these as the current key.
+enum SigningKey {
+    Hs256(Hmac<Sha256>),
+    Hs512(Hmac<Sha512>),
+    Rs256(PKeyWithDigest<Private>),
+}
+
+/// The verifying counterpart of [`SigningKey`], kept for both the current key
+/// and every retired key so their tokens stay valid until they expire.
+enum VerifyingKey {
+    Hs256(Hmac<Sha256>),
+    Hs512(Hmac<Sha512>),
+    Rs256(PKeyWithDigest<Public>),
+}
+
+impl SigningKey {
+    fn algorithm(&self) -> jwt::AlgorithmType {
+        match self {
+            SigningKey::Hs256(_) => jwt::AlgorithmType::Hs256,
+            SigningKey::Hs512(_) => jwt::AlgorithmType::Hs512,
+            SigningKey::Rs256(_) => jwt::AlgorithmType::Rs256,
+        }
+    }
+
+    fn sign(&self, header: jwt::Header, claims: JWTClaims) -> String {
+        let token = jwt::Token::new(header, claims);
+        match self {
+            SigningKey::Hs256(key) => token.sign_with_key(key).unwrap().as_str().to_owned(),
+            SigningKey::Hs512(key) => token.sign_with_key(key).unwrap().as_str().to_owned(),
+            SigningKey::Rs256(key) => token.sign_with_key(key).unwrap().as_str().to_owned(),
+        }
+    }
+}
+
+impl VerifyingKey {
+    fn verify(&self, token: &str) -> core::result::Result<JWTClaims, jwt::Error> {
+        let verified: Token<_> = match self {
+            VerifyingKey::Hs256(key) => VerifyWithKey::verify_with_key(token, key)?,
+            VerifyingKey::Hs512(key) => VerifyWithKey::verify_with_key(token, key)?,
+            VerifyingKey::Rs256(key) => VerifyWithKey::verify_with_key(token, key)?,
+        };
+        Ok(verified.claims().clone())
+    }
+}
+
+/// A ring of JWT keys supporting rotation: tokens are always signed with the
+/// current key (whose `kid` is embedded in the header), while tokens signed by
+/// any retired key still verify until they expire.
+struct JwtKeyring {
+    signing_kid: String,
+    signing: SigningKey,
+    verifying: HashMap<String, VerifyingKey>,
+}
+
+impl JwtKeyring {
+    fn sign(&self, claims: JWTClaims) -> String {
+        let header = jwt::Header {
+            algorithm: self.signing.algorithm(),
+            key_id: Some(self.signing_kid.clone()),
+            ..Default::default()
+        };
+        self.signing.sign(header, claims)
+    }
+
+    /// Verify a token by selecting the verifying key named by its `kid` header.
+    fn verify(&self, token: &str) -> core::result::Result<JWTClaims, jwt::Error> {
+        let unverified: jwt::Token<jwt::Header, JWTClaims, _> =
+            jwt::Token::parse_unverified(token)?;
+        let kid = unverified
+            .header()
+            .key_id
+            .as_deref()
+            .unwrap_or(&self.signing_kid);
+        let key = self
+            .verifying
+            .get(kid)
+            .ok_or(jwt::Error::NoKeyWithKeyId(kid.to_owned()))?;
+        key.verify(token)
+    }
+}
+
+/// Configuration for a single JWT key: its `kid`, the algorithm it uses, and
+/// the key material (an HMAC secret, or a PEM private key for the signing key /
+/// PEM public key for retired verification-only keys).
+#[derive(Clone)]
+struct JwtKeyConfig {
+    kid: String,
+    algorithm: String,
+    material: String,
+}
+
+/// The resolved JWT signing configuration, built from [`Configuration`] and
+/// cloned into each worker so it can construct its own keyring.
+#[derive(Clone)]
+struct JwtConfig {
+    current: JwtKeyConfig,
+    previous: Vec<JwtKeyConfig>,
+    access_token_minutes: i64,
+}
+
+fn signing_key(cfg: &JwtKeyConfig) -> core::result::Result<SigningKey, Error> {
+    Ok(match cfg.algorithm.as_str() {
+        "HS256" => SigningKey::Hs256(Hmac::new_varkey(cfg.material.as_bytes()).unwrap()),
+        "HS512" => SigningKey::Hs512(Hmac::new_varkey(cfg.material.as_bytes()).unwrap()),
+        "RS256" => SigningKey::Rs256(PKeyWithDigest {
+            digest: MessageDigest::sha256(),
+            key: PKey::private_key_from_pem(cfg.material.as_bytes())
+                .map_err(|e| Error::AuthenticationError(format!("Invalid RS256 key: {}", e)))?,
+        }),
+        other => {
+            return Err(Error::AuthenticationError(format!(
+                "Unknown JWT algorithm: {}",
+                other
+            )))
+        }
+    })
+}
+
+fn verifying_key(cfg: &JwtKeyConfig) -> core::result::Result<VerifyingKey, Error> {
+    Ok(match cfg.algorithm.as_str() {
+        "HS256" => VerifyingKey::Hs256(Hmac::new_varkey(cfg.material.as_bytes()).unwrap()),
+        "HS512" => VerifyingKey::Hs512(Hmac::new_varkey(cfg.material.as_bytes()).unwrap()),
+        "RS256" => VerifyingKey::Rs256(PKeyWithDigest {
+            digest: MessageDigest::sha256(),
+            key: PKey::public_key_from_pem(cfg.material.as_bytes())
+                .map_err(|e| Error::AuthenticationError(format!("Invalid RS256 key: {}", e)))?,
+        }),
+        other => {
+            return Err(Error::AuthenticationError(format!(
+                "Unknown JWT algorithm: {}",
+                other
+            )))
+        }
+    })
+}
+
+/// Verifying key for the current (signing) entry. For RS256 the public half is
+/// derived from the configured private key; HMAC keys verify with the secret.
+fn current_verifying_key(cfg: &JwtKeyConfig) -> core::result::Result<VerifyingKey, Error> {
+    if cfg.algorithm == "RS256" {
+        let private = PKey::private_key_from_pem(cfg.material.as_bytes())
+            .map_err(|e| Error::AuthenticationError(format!("Invalid RS256 key: {}", e)))?;
+        let public_pem = private
+            .public_key_to_pem()
+            .map_err(|e| Error::AuthenticationError(format!("Invalid RS256 key: {}", e)))?;
+        Ok(VerifyingKey::Rs256(PKeyWithDigest {
+            digest: MessageDigest::sha256(),
+            key: PKey::public_key_from_pem(&public_pem)
+                .map_err(|e| Error::AuthenticationError(format!("Invalid RS256 key: {}", e)))?,
+        }))
+    } else {
+        verifying_key(cfg)
+    }
+}
+
+fn build_keyring(cfg: &JwtConfig) -> core::result::Result<JwtKeyring, Error> {
+    let mut verifying = HashMap::new();
+    verifying.insert(cfg.current.kid.clone(), current_verifying_key(&cfg.current)?);
+    for previous in &cfg.previous {
+        verifying.insert(previous.kid.clone(), verifying_key(previous)?);
+    }
+    Ok(JwtKeyring {
+        signing_kid: cfg.current.kid.clone(),
+        signing: signing_key(&cfg.current)?,
+        verifying,
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 struct JWTClaims {
     exp: DateTime<Utc>,
     user: String,
     groups: HashSet<String>,
+    /// Whether every required authentication factor was satisfied. A token
+    /// issued after a successful bind but still awaiting the TOTP code carries
+    /// `false`, and is not accepted on the `/api` scope.
+    totp: bool,
+}
+
+/// Follow-up request for the second factor: the partial access token issued by
+/// `/authorize` is sent back as the `token` cookie, and the six-digit code in
+/// the body is checked against the user's stored secret.
+#[derive(Serialize, Deserialize, Clone)]
+struct TotpRequest {
+    code: String,
 }
 
 async fn index(req: HttpRequest) -> actix_web::Result<NamedFile> {
@@ -69,17 +260,115 @@ where
         .unwrap_or_else(error_to_http_response)
 }
 
-fn create_jwt(key: &Hmac<Sha512>, user: String, groups: HashSet<String>) -> SignedToken {
+fn create_jwt(
+    keyring: &JwtKeyring,
+    user: String,
+    groups: HashSet<String>,
+    totp: bool,
+    lifetime_minutes: i64,
+) -> String {
     let claims = JWTClaims {
-        exp: Utc::now() + chrono::Duration::days(1),
+        exp: Utc::now() + chrono::Duration::minutes(lifetime_minutes),
         user,
         groups,
+        totp,
     };
-    let header = jwt::Header {
-        algorithm: jwt::AlgorithmType::Hs512,
-        ..Default::default()
-    };
-    jwt::Token::new(header, claims).sign_with_key(key).unwrap()
+    keyring.sign(claims)
+}
+
+/// Compute the RFC 6238 code for a single 30-second counter value.
+fn totp_at(key: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(key).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = ((u32::from(digest[offset]) & 0x7f) << 24)
+        | (u32::from(digest[offset + 1]) << 16)
+        | (u32::from(digest[offset + 2]) << 8)
+        | u32::from(digest[offset + 3]);
+    binary % 1_000_000
+}
+
+/// Constant-time comparison of two byte slices, so a mismatching code doesn't
+/// leak how many leading digits were correct through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Verify a submitted six-digit code against a base32 secret, tolerating a
+/// one-step clock skew on either side of the current time window. On success
+/// the matching counter step is returned so the caller can reject replays.
+fn verify_totp(secret: &str, code: &str) -> Option<i64> {
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+    let counter = Utc::now().timestamp() / 30;
+    [-1i64, 0, 1].iter().find_map(|offset| {
+        let step = counter + offset;
+        if step < 0 {
+            return None;
+        }
+        let candidate = format!("{:06}", totp_at(&key, step as u64));
+        if constant_time_eq(candidate.as_bytes(), code.as_bytes()) {
+            Some(step)
+        } else {
+            None
+        }
+    })
+}
+
+/// Challenge returned by `/authorize` when the user has a second factor
+/// configured and must complete it through `/authorize/totp`.
+#[derive(Serialize, Deserialize)]
+struct TotpChallenge {
+    totp_required: bool,
+}
+
+/// Persist a fresh refresh token for the user and build the fully-authenticated
+/// response, setting the access-token, refresh-token and user_id cookies.
+async fn build_session_response<Backend>(
+    data: &web::Data<AppState<Backend>>,
+    user: String,
+    groups: HashSet<String>,
+) -> core::result::Result<HttpResponse, Error>
+where
+    Backend: BackendHandler + 'static,
+{
+    let refresh_token = Uuid::new_v4().to_string();
+    data.backend_handler
+        .register_refresh_token(user.clone(), refresh_token.clone())
+        .await?;
+    let token = create_jwt(
+        &data.jwt_keyring,
+        user.clone(),
+        groups,
+        true,
+        data.access_token_minutes,
+    );
+    Ok(HttpResponse::Ok()
+        .cookie(
+            // Scoped to "/" so the session token reaches the API, the refresh
+            // endpoint and the OIDC provider endpoints alike.
+            Cookie::build("token", token.as_str())
+                .max_age(data.access_token_minutes.minutes())
+                .path("/")
+                .http_only(true)
+                .finish(),
+        )
+        .cookie(
+            Cookie::build("refresh_token", refresh_token)
+                .max_age(REFRESH_TOKEN_DAYS.days())
+                .path("/auth/refresh")
+                .http_only(true)
+                .finish(),
+        )
+        .cookie(
+            Cookie::build("user_id", &user)
+                .max_age(REFRESH_TOKEN_DAYS.days())
+                .finish(),
+        )
+        .body(token.as_str().to_owned()))
 }
 
 async fn post_authorize<Backend>(
@@ -90,101 +379,663 @@ where
     Backend: BackendHandler + 'static,
 {
     let req: BindRequest = request.clone();
+    let user = request.name.clone();
+    let result = async {
+        data.backend_handler.bind(req).await?;
+        // The bind succeeded, so fetch the groups needed to build the JWT token.
+        let groups = data.backend_handler.get_user_groups(user.clone()).await?;
+        if data.backend_handler.totp_enabled(user.clone()).await? {
+            // First factor only: hand back a partial token and ask the client to
+            // complete the flow through /authorize/totp.
+            let token = create_jwt(
+                &data.jwt_keyring,
+                user.clone(),
+                groups,
+                false,
+                data.access_token_minutes,
+            );
+            Ok(HttpResponse::Ok()
+                .cookie(
+                    Cookie::build("token", token.as_str())
+                        .max_age(data.access_token_minutes.minutes())
+                        .path("/authorize")
+                        .http_only(true)
+                        .finish(),
+                )
+                .json(TotpChallenge {
+                    totp_required: true,
+                }))
+        } else {
+            build_session_response(&data, user.clone(), groups).await
+        }
+    }
+    .await;
+    match result {
+        Ok(response) => ApiResult::Right(response),
+        Err(error) => error_to_http_response(error),
+    }
+}
+
+async fn post_authorize_totp<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+    body: web::Json<TotpRequest>,
+) -> ApiResult<String>
+where
+    Backend: BackendHandler + 'static,
+{
+    // The partial token from the first factor proves the password step passed.
+    let partial = match request.cookie("token") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return ApiResult::Right(HttpResponse::Unauthorized().body("Missing partial token"))
+        }
+    };
+    let result = async {
+        let claims = data
+            .jwt_keyring
+            .verify(partial.as_str())
+            .map_err(|_| Error::AuthenticationError("Invalid token".to_owned()))?;
+        if claims.exp.lt(&Utc::now()) {
+            return Err(Error::AuthenticationError("Expired token".to_owned()));
+        }
+        let user = claims.user.clone();
+        let secret = data
+            .backend_handler
+            .get_totp_secret(user.clone())
+            .await?
+            .ok_or_else(|| Error::AuthenticationError("TOTP is not configured".to_owned()))?;
+        let step = verify_totp(&secret, &body.code)
+            .ok_or_else(|| Error::AuthenticationError("Invalid TOTP code".to_owned()))?;
+        // Atomically advance the last-accepted step (RFC 6238 §5.2): the backend
+        // only stores `step` if it is strictly greater than the stored value, so
+        // concurrent submissions of the same code can't both win the check.
+        if !data
+            .backend_handler
+            .advance_totp_last_step(user.clone(), step)
+            .await?
+        {
+            return Err(Error::AuthenticationError(
+                "TOTP code was already used".to_owned(),
+            ));
+        }
+        build_session_response(&data, user, claims.groups.clone()).await
+    }
+    .await;
+    match result {
+        Ok(response) => ApiResult::Right(response),
+        Err(error) => error_to_http_response(error),
+    }
+}
+
+async fn refresh<Backend>(data: web::Data<AppState<Backend>>, request: HttpRequest) -> HttpResponse
+where
+    Backend: BackendHandler + 'static,
+{
+    let refresh_token = match request.cookie("refresh_token") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("Missing refresh token"),
+    };
+    // Rotating the token invalidates the presented value, so a replayed refresh
+    // token is rejected on its second use.
     data.backend_handler
-        .bind(req)
-        // If the authentication was successful, we need to fetch the groups to create the JWT
-        // token.
-        .and_then(|_| data.backend_handler.get_user_groups(request.name.clone()))
+        .rotate_refresh_token(refresh_token)
+        .and_then(|rotated| {
+            data.backend_handler
+                .get_user_groups(rotated.user.clone())
+                .map_ok(move |groups| (rotated, groups))
+        })
         .await
-        .map(|groups| {
-            let token = create_jwt(&data.jwt_key, request.name.clone(), groups);
-            ApiResult::Right(
-                HttpResponse::Ok()
-                    .cookie(
-                        Cookie::build("token", token.as_str())
-                            .max_age(1.days())
-                            .path("/api")
-                            .http_only(true)
-                            .finish(),
-                    )
-                    .cookie(
-                        Cookie::build("user_id", &request.name)
-                            .max_age(1.days())
-                            .finish(),
-                    )
-                    .body(token.as_str().to_owned()),
-            )
+        .map(|(rotated, groups)| {
+            let token = create_jwt(
+                &data.jwt_keyring,
+                rotated.user.clone(),
+                groups,
+                true,
+                data.access_token_minutes,
+            );
+            HttpResponse::Ok()
+                .cookie(
+                    Cookie::build("token", token.as_str())
+                        .max_age(data.access_token_minutes.minutes())
+                        .path("/")
+                        .http_only(true)
+                        .finish(),
+                )
+                .cookie(
+                    Cookie::build("refresh_token", rotated.token)
+                        .max_age(REFRESH_TOKEN_DAYS.days())
+                        .path("/auth/refresh")
+                        .http_only(true)
+                        .finish(),
+                )
+                .body(token.as_str().to_owned())
         })
-        .unwrap_or_else(error_to_http_response)
+        .unwrap_or_else(|e| match e {
+            Error::AuthenticationError(_) => HttpResponse::Unauthorized(),
+            Error::DatabaseError(_) => HttpResponse::InternalServerError(),
+        }
+        .body(e.to_string()))
 }
 
-fn api_config<Backend>(cfg: &mut web::ServiceConfig)
+async fn logout<Backend>(data: web::Data<AppState<Backend>>, request: HttpRequest) -> HttpResponse
 where
     Backend: BackendHandler + 'static,
 {
-    let json_config = web::JsonConfig::default()
-        .limit(4096)
-        .error_handler(|err, _req| {
-            // create custom error response
-            log::error!("API error: {}", err);
-            let msg = err.to_string();
-            actix_web::error::InternalError::from_response(
-                err,
-                HttpResponse::BadRequest().body(msg).into(),
+    // The user must be derived from the verified session token, never from the
+    // client-controlled user_id cookie, or anyone could revoke anyone's tokens.
+    let user = match request
+        .cookie("token")
+        .and_then(|cookie| data.jwt_keyring.verify(cookie.value()).ok())
+        .filter(|claims| claims.exp.gt(&Utc::now()))
+    {
+        Some(claims) => claims.user,
+        None => return HttpResponse::Unauthorized().body("Login required"),
+    };
+    match data.backend_handler.revoke_tokens(user).await {
+        Ok(()) => HttpResponse::Ok()
+            .cookie(
+                Cookie::build("refresh_token", "")
+                    .max_age(0.days())
+                    .path("/auth/refresh")
+                    .http_only(true)
+                    .finish(),
             )
-            .into()
-        });
-    cfg.service(
-        web::resource("/users")
-            .app_data(json_config)
-            .route(web::post().to(user_list_handler::<Backend>)),
-    );
+            .cookie(
+                Cookie::build("token", "")
+                    .max_age(0.days())
+                    .path("/")
+                    .http_only(true)
+                    .finish(),
+            )
+            .finish(),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Claims for the OpenID Connect ID token. Unlike [`JWTClaims`], which secures
+/// lldap's own API, this token is signed with the asymmetric OIDC key so that
+/// relying parties can verify it against the published JWKS.
+#[derive(Serialize, Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    exp: i64,
+    iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+}
+
+/// OpenID Connect discovery document, served at
+/// `/.well-known/openid-configuration`.
+#[derive(Serialize)]
+struct OidcDiscovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    response_types_supported: Vec<String>,
+    subject_types_supported: Vec<String>,
+    id_token_signing_alg_values_supported: Vec<String>,
+    code_challenge_methods_supported: Vec<String>,
+}
+
+/// A single RSA public key in JWK form.
+#[derive(Serialize)]
+struct Jwk {
+    kty: String,
+    #[serde(rename = "use")]
+    use_: String,
+    alg: String,
+    kid: String,
+    n: String,
+    e: String,
+}
+
+#[derive(Serialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// Query parameters of the `/oidc/authorize` authorization-code request.
+#[derive(Deserialize)]
+struct OidcAuthorizeRequest {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    #[serde(default)]
+    scope: String,
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+    #[serde(default)]
+    code_challenge: Option<String>,
+    #[serde(default)]
+    code_challenge_method: Option<String>,
+}
+
+/// Form body of the `/oidc/token` authorization-code exchange.
+#[derive(Deserialize)]
+struct OidcTokenRequest {
+    grant_type: String,
+    code: String,
+    redirect_uri: String,
+    client_id: String,
+    #[serde(default)]
+    code_verifier: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OidcTokenResponse {
+    access_token: String,
+    token_type: String,
+    expires_in: i64,
+    id_token: String,
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Build the RS256 signer from the configured private key PEM.
+fn oidc_signer(pem: &str) -> core::result::Result<PKeyWithDigest<Private>, Error> {
+    let key = PKey::private_key_from_pem(pem.as_bytes())
+        .map_err(|e| Error::AuthenticationError(format!("Invalid OIDC key: {}", e)))?;
+    Ok(PKeyWithDigest {
+        digest: MessageDigest::sha256(),
+        key,
+    })
 }
 
-async fn token_validator<Backend>(
-    req: ServiceRequest,
-    credentials: BearerAuth,
-) -> Result<ServiceRequest, actix_web::Error>
+/// Verify a PKCE `code_verifier` against the stored challenge. A missing
+/// challenge means the client didn't use PKCE, which we accept.
+fn verify_pkce(challenge: Option<&str>, method: Option<&str>, verifier: Option<&str>) -> bool {
+    let challenge = match challenge {
+        Some(challenge) => challenge,
+        None => return true,
+    };
+    let verifier = match verifier {
+        Some(verifier) => verifier,
+        None => return false,
+    };
+    match method.unwrap_or("plain") {
+        "S256" => base64url(Sha256::digest(verifier.as_bytes()).as_slice()) == challenge,
+        "plain" => verifier == challenge,
+        _ => false,
+    }
+}
+
+async fn oidc_discovery<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: BackendHandler + 'static,
+{
+    let issuer = data.oidc_issuer.clone();
+    HttpResponse::Ok().json(OidcDiscovery {
+        authorization_endpoint: format!("{}/oidc/authorize", issuer),
+        token_endpoint: format!("{}/oidc/token", issuer),
+        jwks_uri: format!("{}/oidc/jwks", issuer),
+        issuer,
+        response_types_supported: vec!["code".to_owned()],
+        subject_types_supported: vec!["public".to_owned()],
+        id_token_signing_alg_values_supported: vec!["RS256".to_owned()],
+        code_challenge_methods_supported: vec!["plain".to_owned(), "S256".to_owned()],
+    })
+}
+
+async fn oidc_jwks<Backend>(data: web::Data<AppState<Backend>>) -> HttpResponse
+where
+    Backend: BackendHandler + 'static,
+{
+    let rsa = match PKey::private_key_from_pem(data.oidc_private_pem.as_bytes())
+        .and_then(|key| key.rsa())
+    {
+        Ok(rsa) => rsa,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Invalid OIDC key: {}", e)),
+    };
+    HttpResponse::Ok().json(JwkSet {
+        keys: vec![Jwk {
+            kty: "RSA".to_owned(),
+            use_: "sig".to_owned(),
+            alg: "RS256".to_owned(),
+            kid: "oidc".to_owned(),
+            n: base64url(&rsa.n().to_vec()),
+            e: base64url(&rsa.e().to_vec()),
+        }],
+    })
+}
+
+async fn oidc_authorize<Backend>(
+    data: web::Data<AppState<Backend>>,
+    request: HttpRequest,
+    query: web::Query<OidcAuthorizeRequest>,
+) -> HttpResponse
 where
     Backend: BackendHandler + 'static,
 {
-    let state = req
-        .app_data::<web::Data<AppState<Backend>>>()
-        .expect("Invalid app config");
-    let token: Token<_> = VerifyWithKey::verify_with_key(credentials.token(), &state.jwt_key)
-        .map_err(|_| ErrorUnauthorized("Invalid JWT"))?;
-    if token.claims().exp.lt(&Utc::now()) {
-        return Err(ErrorUnauthorized("Expired JWT"));
-    }
-    let groups = &token.claims().groups;
-    if groups.contains("lldap_admin") {
-        debug!("Got authorized token for user {}", &token.claims().user);
-        Ok(req)
+    if query.response_type != "code" {
+        return HttpResponse::BadRequest().body("Only the authorization-code flow is supported");
+    }
+    // The resource owner must already hold a valid lldap session.
+    let user = match request
+        .cookie("token")
+        .and_then(|cookie| data.jwt_keyring.verify(cookie.value()).ok())
+        .filter(|claims| claims.exp.gt(&Utc::now()) && claims.totp)
+    {
+        Some(claims) => claims.user.clone(),
+        None => return HttpResponse::Unauthorized().body("Login required"),
+    };
+    let result = async {
+        let client = data
+            .backend_handler
+            .get_oidc_client(query.client_id.clone())
+            .await?
+            .ok_or_else(|| Error::AuthenticationError("Unknown client".to_owned()))?;
+        if !client.redirect_uris.contains(&query.redirect_uri) {
+            return Err(Error::AuthenticationError("Invalid redirect_uri".to_owned()));
+        }
+        let code = Uuid::new_v4().to_string();
+        data.backend_handler
+            .store_authorization_code(AuthorizationCode {
+                code: code.clone(),
+                client_id: query.client_id.clone(),
+                redirect_uri: query.redirect_uri.clone(),
+                user: user.clone(),
+                nonce: query.nonce.clone(),
+                code_challenge: query.code_challenge.clone(),
+                code_challenge_method: query.code_challenge_method.clone(),
+                scopes: query.scope.clone(),
+            })
+            .await?;
+        Ok(code)
+    }
+    .await;
+    match result {
+        Ok(code) => {
+            // Percent-encode the values so reserved characters in `state` (RPs
+            // put arbitrary/base64 values there) can't corrupt the query or
+            // inject into the Location header.
+            let mut location = format!(
+                "{}?code={}",
+                query.redirect_uri,
+                utf8_percent_encode(&code, NON_ALPHANUMERIC)
+            );
+            if let Some(state) = &query.state {
+                location.push_str(&format!(
+                    "&state={}",
+                    utf8_percent_encode(state, NON_ALPHANUMERIC)
+                ));
+            }
+            HttpResponse::Found()
+                .header(actix_http::header::LOCATION, location)
+                .finish()
+        }
+        Err(e) => match e {
+            Error::AuthenticationError(_) => HttpResponse::BadRequest(),
+            Error::DatabaseError(_) => HttpResponse::InternalServerError(),
+        }
+        .body(e.to_string()),
+    }
+}
+
+/// Exchange an authorization code for tokens. Only public clients are
+/// supported: authenticity rests on the single-use code and PKCE, not on a
+/// client secret, so there is no confidential-client authentication here.
+async fn oidc_token<Backend>(
+    data: web::Data<AppState<Backend>>,
+    body: web::Form<OidcTokenRequest>,
+) -> HttpResponse
+where
+    Backend: BackendHandler + 'static,
+{
+    if body.grant_type != "authorization_code" {
+        return HttpResponse::BadRequest().body("Unsupported grant_type");
+    }
+    let result = async {
+        let code = data
+            .backend_handler
+            .consume_authorization_code(body.code.clone())
+            .await?
+            .ok_or_else(|| Error::AuthenticationError("Invalid or expired code".to_owned()))?;
+        if code.client_id != body.client_id || code.redirect_uri != body.redirect_uri {
+            return Err(Error::AuthenticationError("Code does not match client".to_owned()));
+        }
+        if !verify_pkce(
+            code.code_challenge.as_deref(),
+            code.code_challenge_method.as_deref(),
+            body.code_verifier.as_deref(),
+        ) {
+            return Err(Error::AuthenticationError("PKCE verification failed".to_owned()));
+        }
+        // An ID token is only meaningful for an OpenID Connect request.
+        if !code.scopes.split_whitespace().any(|scope| scope == "openid") {
+            return Err(Error::AuthenticationError(
+                "The openid scope is required".to_owned(),
+            ));
+        }
+        let groups = data.backend_handler.get_user_groups(code.user.clone()).await?;
+        let now = Utc::now();
+        let claims = IdTokenClaims {
+            iss: data.oidc_issuer.clone(),
+            sub: code.user.clone(),
+            aud: code.client_id.clone(),
+            iat: now.timestamp(),
+            exp: (now + chrono::Duration::minutes(data.access_token_minutes)).timestamp(),
+            nonce: code.nonce.clone(),
+        };
+        let header = jwt::Header {
+            algorithm: jwt::AlgorithmType::Rs256,
+            key_id: Some("oidc".to_owned()),
+            ..Default::default()
+        };
+        let signer = oidc_signer(&data.oidc_private_pem)?;
+        let id_token = jwt::Token::new(header, claims)
+            .sign_with_key(&signer)
+            .map_err(|e| Error::AuthenticationError(format!("Failed to sign ID token: {}", e)))?
+            .as_str()
+            .to_owned();
+        // Reuse the internal access token so the client can also call the API.
+        let access_token = create_jwt(
+            &data.jwt_keyring,
+            code.user.clone(),
+            groups,
+            true,
+            data.access_token_minutes,
+        );
+        Ok((access_token, id_token))
+    }
+    .await;
+    match result {
+        Ok((access_token, id_token)) => HttpResponse::Ok().json(OidcTokenResponse {
+            access_token,
+            token_type: "Bearer".to_owned(),
+            expires_in: data.access_token_minutes * 60,
+            id_token,
+        }),
+        Err(e) => match e {
+            Error::AuthenticationError(_) => HttpResponse::BadRequest(),
+            Error::DatabaseError(_) => HttpResponse::InternalServerError(),
+        }
+        .body(e.to_string()),
+    }
+}
+
+fn api_config<Backend>(admin_group: String) -> impl Fn(&mut web::ServiceConfig)
+where
+    Backend: BackendHandler + 'static,
+{
+    move |cfg: &mut web::ServiceConfig| {
+        let json_config = web::JsonConfig::default()
+            .limit(4096)
+            .error_handler(|err, _req| {
+                // create custom error response
+                log::error!("API error: {}", err);
+                let msg = err.to_string();
+                actix_web::error::InternalError::from_response(
+                    err,
+                    HttpResponse::BadRequest().body(msg).into(),
+                )
+                .into()
+            });
+        // Listing users requires the configured admin group. Future endpoints
+        // (e.g. a self-service `/me`) can demand a different set, or an empty
+        // set to accept any authenticated token.
+        let admin_only: HashSet<String> = std::iter::once(admin_group).collect();
+        cfg.service(
+            web::resource("/users")
+                .app_data(json_config)
+                // Authenticate and compare the caller's groups against this
+                // route's requirement, captured directly in the closure.
+                .wrap_fn(move |req, srv| {
+                    let state = req
+                        .app_data::<web::Data<AppState<Backend>>>()
+                        .expect("Invalid app config")
+                        .clone();
+                    let header = req
+                        .headers()
+                        .get(actix_http::header::AUTHORIZATION)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_owned);
+                    let required = admin_only.clone();
+                    let fut = srv.call(req);
+                    async move {
+                        let groups = authenticate(&state, header.as_deref()).await?;
+                        check_group_requirement(&groups, &required)?;
+                        fut.await
+                    }
+                    .boxed_local()
+                })
+                .route(web::post().to(user_list_handler::<Backend>)),
+        );
+    }
+}
+
+/// Reject the request unless the authenticated user belongs to at least one of
+/// the route's required groups. An empty requirement accepts any authenticated
+/// user.
+fn check_group_requirement(
+    groups: &HashSet<String>,
+    required: &HashSet<String>,
+) -> Result<(), actix_web::Error> {
+    if required.is_empty() || required.iter().any(|group| groups.contains(group)) {
+        Ok(())
     } else {
-        Err(ErrorUnauthorized(
-            "JWT error: User is not in group lldap_admin",
-        ))
+        let mut names: Vec<&str> = required.iter().map(String::as_str).collect();
+        names.sort_unstable();
+        Err(ErrorForbidden(format!(
+            "User is not in any of the required groups: {}",
+            names.join(", ")
+        )))
     }
 }
 
-fn http_config<Backend>(cfg: &mut web::ServiceConfig, backend_handler: Backend, jwt_secret: String)
+/// Authenticate an `/api` request from its `Authorization` header and return the
+/// caller's groups. A `Bearer` credential is validated as an access JWT (or the
+/// cookie rewritten into one), while a `Basic` credential is bound directly
+/// against the backend so that programmatic clients don't need to run the
+/// `/authorize` dance first. The per-route group check is applied separately.
+async fn authenticate<Backend>(
+    state: &web::Data<AppState<Backend>>,
+    header: Option<&str>,
+) -> Result<HashSet<String>, actix_web::Error>
 where
     Backend: BackendHandler + 'static,
 {
+    let header = header.ok_or_else(|| ErrorUnauthorized("Missing authorization header"))?;
+    let groups = if let Some(token) = header.strip_prefix("Bearer ") {
+        let claims = state
+            .jwt_keyring
+            .verify(token)
+            .map_err(|_| ErrorUnauthorized("Invalid JWT"))?;
+        if claims.exp.lt(&Utc::now()) {
+            return Err(ErrorUnauthorized("Expired JWT"));
+        }
+        if !claims.totp {
+            return Err(ErrorUnauthorized("Second factor required"));
+        }
+        debug!("Got authorized token for user {}", &claims.user);
+        claims.groups
+    } else if let Some(credentials) = header.strip_prefix("Basic ") {
+        let decoded = base64::decode(credentials)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| ErrorUnauthorized("Invalid Basic credentials"))?;
+        let (name, password) = decoded
+            .split_once(':')
+            .ok_or_else(|| ErrorUnauthorized("Invalid Basic credentials"))?;
+        state
+            .backend_handler
+            .bind(BindRequest {
+                name: name.to_owned(),
+                password: password.to_owned(),
+            })
+            .await
+            .map_err(|_| ErrorUnauthorized("Invalid credentials"))?;
+        // Password-only Basic auth must not bypass the second factor: users with
+        // TOTP enabled have to go through the /authorize flow.
+        if state
+            .backend_handler
+            .totp_enabled(name.to_owned())
+            .await
+            .map_err(|_| ErrorUnauthorized("Could not check second factor"))?
+        {
+            return Err(ErrorUnauthorized(
+                "Second factor required: use the /authorize login flow",
+            ));
+        }
+        debug!("Authenticated user {} via Basic credentials", name);
+        state
+            .backend_handler
+            .get_user_groups(name.to_owned())
+            .await
+            .map_err(|_| ErrorUnauthorized("Could not fetch user groups"))?
+    } else {
+        return Err(ErrorUnauthorized("Unsupported authorization scheme"));
+    };
+    Ok(groups)
+}
+
+fn http_config<Backend>(
+    cfg: &mut web::ServiceConfig,
+    backend_handler: Backend,
+    jwt: JwtConfig,
+    admin_group: String,
+    oidc_private_pem: String,
+    oidc_issuer: String,
+) where
+    Backend: BackendHandler + 'static,
+{
+    let access_token_minutes = jwt.access_token_minutes;
     cfg.data(AppState::<Backend> {
         backend_handler,
-        jwt_key: Hmac::new_varkey(&jwt_secret.as_bytes()).unwrap(),
+        jwt_keyring: build_keyring(&jwt).expect("Invalid JWT configuration"),
+        access_token_minutes,
+        oidc_private_pem,
+        oidc_issuer,
     })
     // Serve index.html and main.js, and default to index.html.
     .route(
         "/{filename:(index\\.html|main\\.js)?}",
         web::get().to(index),
     )
-    .service(web::resource("/authorize").route(web::post().to(post_authorize::<Backend>)))
+    .service(
+        web::scope("/authorize")
+            .service(web::resource("").route(web::post().to(post_authorize::<Backend>)))
+            .service(
+                web::resource("/totp").route(web::post().to(post_authorize_totp::<Backend>)),
+            ),
+    )
+    // Refresh-token endpoints. The refresh cookie is scoped here so it is never
+    // sent to the API itself.
+    .service(
+        web::scope("/auth")
+            .service(web::resource("/refresh").route(web::post().to(refresh::<Backend>)))
+            .service(web::resource("/logout").route(web::post().to(logout::<Backend>))),
+    )
     // API endpoint.
     .service(
         web::scope("/api")
-            .wrap(HttpAuthentication::bearer(token_validator::<Backend>))
             .wrap_fn(|mut req, srv| {
                 if let Some(token_cookie) = req.cookie("token") {
                     if let Ok(header_value) = actix_http::header::HeaderValue::from_str(&format!(
@@ -202,7 +1053,18 @@ where
                 };
                 Box::pin(srv.call(req))
             })
-            .configure(api_config::<Backend>),
+            .configure(api_config::<Backend>(admin_group)),
+    )
+    // OpenID Connect provider endpoints.
+    .service(
+        web::resource("/.well-known/openid-configuration")
+            .route(web::get().to(oidc_discovery::<Backend>)),
+    )
+    .service(
+        web::scope("/oidc")
+            .service(web::resource("/jwks").route(web::get().to(oidc_jwks::<Backend>)))
+            .service(web::resource("/authorize").route(web::get().to(oidc_authorize::<Backend>)))
+            .service(web::resource("/token").route(web::post().to(oidc_token::<Backend>))),
     )
     // Serve the /pkg path with the compiled WASM app.
     .service(Files::new("/pkg", "./app/pkg"));
@@ -213,7 +1075,10 @@ where
     Backend: BackendHandler + 'static,
 {
     pub backend_handler: Backend,
-    pub jwt_key: Hmac<Sha512>,
+    pub jwt_keyring: JwtKeyring,
+    pub access_token_minutes: i64,
+    pub oidc_private_pem: String,
+    pub oidc_issuer: String,
 }
 
 pub fn build_tcp_server<Backend>(
@@ -225,14 +1090,51 @@ where
     Backend: BackendHandler + 'static,
 {
     let http_port = config.http_port.clone();
-    let jwt_secret = config.jwt_secret.clone();
+    // Current signing key plus any retired keys still trusted for verification,
+    // so operators can rotate the secret without dropping live sessions.
+    let jwt = JwtConfig {
+        current: JwtKeyConfig {
+            kid: config.jwt_key_id.clone(),
+            algorithm: config.jwt_algorithm.clone(),
+            material: config.jwt_secret.clone(),
+        },
+        previous: config
+            .jwt_previous_keys
+            .iter()
+            .map(|key| JwtKeyConfig {
+                kid: key.key_id.clone(),
+                algorithm: key.algorithm.clone(),
+                material: key.material.clone(),
+            })
+            .collect(),
+        access_token_minutes: if config.access_token_duration_minutes > 0 {
+            config.access_token_duration_minutes
+        } else {
+            DEFAULT_ACCESS_TOKEN_MINUTES
+        },
+    };
+    let admin_group = config.admin_group.clone();
+    let oidc_private_pem = config.oidc_private_key.clone();
+    let oidc_issuer = config.oidc_issuer.clone();
     server_builder
         .bind("http", ("0.0.0.0", config.http_port), move || {
             let backend_handler = backend_handler.clone();
-            let jwt_secret = jwt_secret.clone();
+            let jwt = jwt.clone();
+            let admin_group = admin_group.clone();
+            let oidc_private_pem = oidc_private_pem.clone();
+            let oidc_issuer = oidc_issuer.clone();
             HttpServiceBuilder::new()
                 .finish(map_config(
-                    App::new().configure(move |cfg| http_config(cfg, backend_handler, jwt_secret)),
+                    App::new().configure(move |cfg| {
+                        http_config(
+                            cfg,
+                            backend_handler,
+                            jwt,
+                            admin_group,
+                            oidc_private_pem,
+                            oidc_issuer,
+                        )
+                    }),
                     |_| AppConfig::default(),
                 ))
                 .tcp()